@@ -8,12 +8,18 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-//! Access to a single thread-local pointer.
+//! Access to a small, fixed number of thread-local pointer slots.
 //!
-//! The runtime will use this for storing ~Task.
+//! The runtime uses `TASK_SLOT` for storing ~Task, but other subsystems
+//! that want a fast thread-local without rolling their own TLS key can
+//! claim one of the other slots via `Slot::new`.
 //!
-//! XXX: Add runtime checks for usage of inconsistent pointer types.
-//! and for overwriting an existing pointer.
+//! In `#[cfg(rtdebug)]` builds, a tag identifying the pointee's type is
+//! stashed alongside each slot's pointer so that `put`/`take`/`borrow` can
+//! `rtabort!` on a type mismatch or on an attempt to overwrite an existing
+//! pointer, rather than silently transmuting the wrong type. These checks
+//! add a branch to every access, so they're compiled out entirely unless
+//! `rtdebug` is set.
 
 #[allow(dead_code)];
 
@@ -28,23 +34,68 @@ pub use self::native::*;
 #[cfg(not(windows), not(target_os = "android"))]
 pub use self::compiled::*;
 
+/// Identifies one of a small, fixed number of independent thread-local
+/// pointer slots.
+pub struct Slot(uint);
+
+impl Slot {
+    /// Claim a slot other than `TASK_SLOT` for a subsystem's own
+    /// thread-local use.
+    ///
+    /// # Failure
+    ///
+    /// Aborts if `idx >= NUM_SLOTS`.
+    pub fn new(idx: uint) -> Slot {
+        check_slot(idx);
+        Slot(idx)
+    }
+}
+
+/// Number of slots available. Kept small since each one costs a word of
+/// thread-local storage (and, in `#[cfg(rtdebug)]` builds, a second word
+/// for its type tag).
+pub static NUM_SLOTS: uint = 4;
+
+/// The slot the scheduler keeps its `~Task` in. `put`/`take`/`borrow` and
+/// friends, with no slot argument, all operate on this slot.
+pub static TASK_SLOT: Slot = Slot(0);
+
+/// Abort rather than let an out-of-range slot index fall through to a
+/// plain array-bounds panic, consistent with how the rest of this module
+/// reports misuse via `rtabort!`. Called at the top of every `*_slot`
+/// function (`borrow_slot` gets it through `take_slot`), since a `Slot`'s
+/// index isn't otherwise re-checked once built.
+#[inline]
+fn check_slot(idx: uint) {
+    if idx >= NUM_SLOTS {
+        rtabort!("local_ptr: slot index out of range");
+    }
+}
+
 /// Borrow the thread-local value from thread-local storage.
 /// While the value is borrowed it is not available in TLS.
 ///
 /// # Safety note
 ///
-/// Does not validate the pointer type.
+/// Does not validate the pointer type outside of `#[cfg(rtdebug)]` builds.
 pub unsafe fn borrow<T>(f: |&mut T|) {
-    let mut value = take();
+    borrow_slot(TASK_SLOT, f)
+}
+
+/// Like `borrow`, but operates on an arbitrary slot rather than
+/// `TASK_SLOT`.
+pub unsafe fn borrow_slot<T>(slot: Slot, f: |&mut T|) {
+    let Slot(idx) = slot;
+    let mut value = take_slot(Slot(idx));
 
     // XXX: Need a different abstraction from 'finally' here to avoid unsafety
     let unsafe_ptr = cast::transmute_mut_region(&mut *value);
     let value_cell = Cell::new(value);
 
-    (|| f(unsafe_ptr)).finally(|| put(value_cell.take()));
+    (|| f(unsafe_ptr)).finally(|| put_slot(Slot(idx), value_cell.take()));
 }
 
-/// Compiled implementation of accessing the runtime local pointer. This is
+/// Compiled implementation of accessing the runtime local pointers. This is
 /// implemented using LLVM's thread_local attribute which isn't necessarily
 /// working on all platforms. This implementation is faster, however, so we use
 /// it wherever possible.
@@ -54,13 +105,22 @@ pub mod compiled {
     use libc::c_void;
     use cast;
     use option::{Option, Some, None};
+    use super::{Slot, NUM_SLOTS, TASK_SLOT};
 
     #[cfg(test)]
     pub use realstd::rt::shouldnt_be_public::RT_TLS_PTR;
 
     #[cfg(not(test))]
     #[thread_local]
-    pub static mut RT_TLS_PTR: *mut c_void = 0 as *mut c_void;
+    pub static mut RT_TLS_PTR: [*mut c_void, ..NUM_SLOTS] =
+        [0 as *mut c_void, ..NUM_SLOTS];
+
+    /// A tag identifying the type last `put` into the correspondingly
+    /// indexed slot of `RT_TLS_PTR`, tracked only in `#[cfg(rtdebug)]`
+    /// builds. See `type_tag` below.
+    #[cfg(rtdebug)]
+    #[thread_local]
+    static mut RT_TLS_TYPE: [uint, ..NUM_SLOTS] = [0, ..NUM_SLOTS];
 
     pub fn init() {}
 
@@ -70,22 +130,47 @@ pub mod compiled {
     ///
     /// # Safety note
     ///
-    /// Does not validate the pointer type.
+    /// In `#[cfg(rtdebug)]` builds, aborts if a pointer is already
+    /// installed. Otherwise does not validate the pointer type.
     #[inline]
     pub unsafe fn put<T>(sched: ~T) {
-        RT_TLS_PTR = cast::transmute(sched)
+        put_slot(TASK_SLOT, sched)
+    }
+
+    /// Like `put`, but for an arbitrary slot rather than `TASK_SLOT`.
+    #[inline]
+    pub unsafe fn put_slot<T>(slot: Slot, sched: ~T) {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        check_empty(idx);
+        record_type::<T>(idx);
+        RT_TLS_PTR[idx] = cast::transmute(sched)
     }
 
     /// Take ownership of a pointer from thread-local storage.
     ///
     /// # Safety note
     ///
-    /// Does not validate the pointer type.
+    /// In `#[cfg(rtdebug)]` builds, aborts if `T` doesn't match the type
+    /// last `put`. Otherwise does not validate the pointer type.
     #[inline]
     pub unsafe fn take<T>() -> ~T {
-        let ptr: ~T = cast::transmute(RT_TLS_PTR);
+        take_slot(TASK_SLOT)
+    }
+
+    /// Like `take`, but for an arbitrary slot rather than `TASK_SLOT`.
+    #[inline]
+    pub unsafe fn take_slot<T>(slot: Slot) -> ~T {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        if RT_TLS_PTR[idx].is_null() {
+            rtabort!("thread-local pointer is null. bogus!");
+        }
+        check_type::<T>(idx);
+        clear_type(idx);
+        let ptr: ~T = cast::transmute(RT_TLS_PTR[idx]);
         // can't use `as`, due to type not matching with `cfg(test)`
-        RT_TLS_PTR = cast::transmute(0);
+        RT_TLS_PTR[idx] = cast::transmute(0);
         ptr
     }
 
@@ -93,39 +178,168 @@ pub mod compiled {
     ///
     /// # Safety note
     ///
-    /// Does not validate the pointer type.
+    /// In `#[cfg(rtdebug)]` builds, aborts if `T` doesn't match the type
+    /// last `put`. Otherwise does not validate the pointer type.
     /// Leaves the old pointer in TLS for speed.
     #[inline]
     pub unsafe fn unsafe_take<T>() -> ~T {
-        cast::transmute(RT_TLS_PTR)
+        unsafe_take_slot(TASK_SLOT)
+    }
+
+    /// Like `unsafe_take`, but for an arbitrary slot rather than
+    /// `TASK_SLOT`.
+    #[inline]
+    pub unsafe fn unsafe_take_slot<T>(slot: Slot) -> ~T {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        if RT_TLS_PTR[idx].is_null() {
+            rtabort!("thread-local pointer is null. bogus!");
+        }
+        check_type::<T>(idx);
+        // The raw pointer is deliberately left in TLS for speed, but the
+        // type tag is cleared so `check_empty` sees this slot as available
+        // to a subsequent `put`/`put_slot` (the scheduler-handoff pattern
+        // this function exists for), rather than aborting on a pointer
+        // that's already been logically taken.
+        clear_type(idx);
+        cast::transmute(RT_TLS_PTR[idx])
     }
 
     /// Check whether there is a thread-local pointer installed.
     pub fn exists() -> bool {
+        exists_slot(TASK_SLOT)
+    }
+
+    /// Like `exists`, but for an arbitrary slot rather than `TASK_SLOT`.
+    pub fn exists_slot(slot: Slot) -> bool {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
         unsafe {
-            RT_TLS_PTR.is_not_null()
+            RT_TLS_PTR[idx].is_not_null()
         }
     }
 
     pub unsafe fn unsafe_borrow<T>() -> *mut T {
-        if RT_TLS_PTR.is_null() {
+        unsafe_borrow_slot(TASK_SLOT)
+    }
+
+    /// Like `unsafe_borrow`, but for an arbitrary slot rather than
+    /// `TASK_SLOT`.
+    pub unsafe fn unsafe_borrow_slot<T>(slot: Slot) -> *mut T {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        if RT_TLS_PTR[idx].is_null() {
             rtabort!("thread-local pointer is null. bogus!");
         }
-        RT_TLS_PTR as *mut T
+        check_type::<T>(idx);
+        RT_TLS_PTR[idx] as *mut T
     }
 
     pub unsafe fn try_unsafe_borrow<T>() -> Option<*mut T> {
-        if RT_TLS_PTR.is_null() {
+        try_unsafe_borrow_slot(TASK_SLOT)
+    }
+
+    /// Like `try_unsafe_borrow`, but for an arbitrary slot rather than
+    /// `TASK_SLOT`.
+    pub unsafe fn try_unsafe_borrow_slot<T>(slot: Slot) -> Option<*mut T> {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        if RT_TLS_PTR[idx].is_null() {
             None
         } else {
-            Some(RT_TLS_PTR as *mut T)
+            check_type::<T>(idx);
+            Some(RT_TLS_PTR[idx] as *mut T)
+        }
+    }
+
+    /// A value that's unique to `T` for the life of the program: the
+    /// address of this very function, monomorphized once per `T`. Serves as
+    /// a poor man's `TypeId` for the `#[cfg(rtdebug)]` sanity checks below.
+    ///
+    /// # Caveat
+    ///
+    /// `type_tag::<A>` and `type_tag::<B>` have byte-identical bodies, so a
+    /// linker that does identical-code-folding across monomorphizations can
+    /// fold them to the same address, making `check_type` silently accept a
+    /// real `A`/`B` mismatch. This is a best-effort debug aid, not a sound
+    /// guarantee.
+    #[cfg(rtdebug)]
+    fn type_tag<T>() -> uint {
+        let marker: fn() -> uint = type_tag::<T>;
+        unsafe { cast::transmute(marker) }
+    }
+
+    /// `TASK_SLOT`'s pointer cell is aliased to `realstd`'s `RT_TLS_PTR`
+    /// under `#[cfg(test)]` (see above), since that's where the real
+    /// scheduler's `put` actually lands. `RT_TLS_TYPE` has no such alias —
+    /// it can't, since `realstd` is a separately-compiled crate this one
+    /// doesn't own — so the tag this crate would read for `TASK_SLOT` was
+    /// never written by that `put` and has nothing meaningful to check.
+    #[cfg(rtdebug, test)]
+    #[inline]
+    fn shares_tag_with_realstd(idx: uint) -> bool {
+        let Slot(task_idx) = TASK_SLOT;
+        idx == task_idx
+    }
+    #[cfg(rtdebug, not(test))]
+    #[inline]
+    fn shares_tag_with_realstd(_idx: uint) -> bool { false }
+
+    /// A tag of `0` means the slot has no owner: either nothing has been
+    /// `put` yet, or the last `take_slot`/`unsafe_take_slot` cleared it on
+    /// the way out. `unsafe_take_slot` deliberately leaves the raw pointer
+    /// in `RT_TLS_PTR` for speed, so `check_empty` must key off the type
+    /// tag rather than pointer nullness.
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn check_empty(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        if RT_TLS_TYPE[idx] != 0 {
+            rtabort!("local_ptr: put() called but a pointer is already installed in this slot");
+        }
+    }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn check_empty(_idx: uint) {}
+
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn record_type<T>(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        RT_TLS_TYPE[idx] = type_tag::<T>();
+    }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn record_type<T>(_idx: uint) {}
+
+    /// Mark a slot as having no owner, without touching `RT_TLS_PTR`. See
+    /// `check_empty`.
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn clear_type(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        RT_TLS_TYPE[idx] = 0;
+    }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn clear_type(_idx: uint) {}
+
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn check_type<T>(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        if RT_TLS_TYPE[idx] != type_tag::<T>() {
+            rtabort!("local_ptr: pointer type mismatch");
         }
     }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn check_type<T>(_idx: uint) {}
 }
 
-/// Native implementation of having the runtime thread-local pointer. This
-/// implementation uses the `thread_local_storage` module to provide a
-/// thread-local value.
+/// Native implementation of having the runtime thread-local pointers. This
+/// implementation uses the `thread_local_storage` module to provide
+/// thread-local values.
 pub mod native {
     use cast;
     use libc::c_void;
@@ -133,27 +347,41 @@ pub mod native {
     use ptr;
     use tls = rt::thread_local_storage;
     use unstable::mutex::{Mutex, MUTEX_INIT};
+    use super::{Slot, NUM_SLOTS, TASK_SLOT};
 
     static mut LOCK: Mutex = MUTEX_INIT;
     static mut INITIALIZED: bool = false;
-    static mut RT_TLS_KEY: tls::Key = -1;
-
-    /// Initialize the TLS key. Other ops will fail if this isn't executed
-    /// first.
+    static mut RT_TLS_KEYS: [tls::Key, ..NUM_SLOTS] = [-1, ..NUM_SLOTS];
+
+    /// A parallel array of TLS keys, tracked only in `#[cfg(rtdebug)]`
+    /// builds, holding the tag of the type last `put` into the
+    /// correspondingly indexed slot of `RT_TLS_KEYS`.
+    #[cfg(rtdebug)]
+    static mut RT_TLS_TYPE_KEYS: [tls::Key, ..NUM_SLOTS] = [-1, ..NUM_SLOTS];
+
+    /// Initialize the TLS key backing `TASK_SLOT`. Other slots are created
+    /// lazily on first use; calling this up front just avoids paying that
+    /// cost under the lock the first time the scheduler installs its
+    /// `~Task`.
     pub fn init() {
         unsafe {
-            LOCK.lock();
-            if !INITIALIZED {
-                tls::create(&mut RT_TLS_KEY);
-                INITIALIZED = true;
-            }
-            LOCK.unlock();
+            let Slot(idx) = TASK_SLOT;
+            create_key(idx);
+            INITIALIZED = true;
         }
     }
 
     pub unsafe fn cleanup() {
         assert!(INITIALIZED);
-        tls::destroy(RT_TLS_KEY);
+        LOCK.lock();
+        for idx in range(0, NUM_SLOTS) {
+            if RT_TLS_KEYS[idx] != -1 {
+                tls::destroy(RT_TLS_KEYS[idx]);
+                RT_TLS_KEYS[idx] = -1;
+            }
+            destroy_type_key(idx);
+        }
+        LOCK.unlock();
         LOCK.destroy();
         INITIALIZED = false;
     }
@@ -162,10 +390,21 @@ pub mod native {
     ///
     /// # Safety note
     ///
-    /// Does not validate the pointer type.
+    /// In `#[cfg(rtdebug)]` builds, aborts if a pointer is already
+    /// installed. Otherwise does not validate the pointer type.
     #[inline]
     pub unsafe fn put<T>(sched: ~T) {
-        let key = tls_key();
+        put_slot(TASK_SLOT, sched)
+    }
+
+    /// Like `put`, but for an arbitrary slot rather than `TASK_SLOT`.
+    #[inline]
+    pub unsafe fn put_slot<T>(slot: Slot, sched: ~T) {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        let key = tls_key(idx);
+        check_empty(idx);
+        record_type::<T>(idx);
         let void_ptr: *mut c_void = cast::transmute(sched);
         tls::set(key, void_ptr);
     }
@@ -174,14 +413,25 @@ pub mod native {
     ///
     /// # Safety note
     ///
-    /// Does not validate the pointer type.
+    /// In `#[cfg(rtdebug)]` builds, aborts if `T` doesn't match the type
+    /// last `put`. Otherwise does not validate the pointer type.
     #[inline]
     pub unsafe fn take<T>() -> ~T {
-        let key = tls_key();
+        take_slot(TASK_SLOT)
+    }
+
+    /// Like `take`, but for an arbitrary slot rather than `TASK_SLOT`.
+    #[inline]
+    pub unsafe fn take_slot<T>(slot: Slot) -> ~T {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        let key = tls_key(idx);
         let void_ptr: *mut c_void = tls::get(key);
         if void_ptr.is_null() {
             rtabort!("thread-local pointer is null. bogus!");
         }
+        check_type::<T>(idx);
+        clear_type(idx);
         let ptr: ~T = cast::transmute(void_ptr);
         tls::set(key, ptr::mut_null());
         return ptr;
@@ -191,23 +441,47 @@ pub mod native {
     ///
     /// # Safety note
     ///
-    /// Does not validate the pointer type.
+    /// In `#[cfg(rtdebug)]` builds, aborts if `T` doesn't match the type
+    /// last `put`. Otherwise does not validate the pointer type.
     /// Leaves the old pointer in TLS for speed.
     #[inline]
     pub unsafe fn unsafe_take<T>() -> ~T {
-        let key = tls_key();
+        unsafe_take_slot(TASK_SLOT)
+    }
+
+    /// Like `unsafe_take`, but for an arbitrary slot rather than
+    /// `TASK_SLOT`.
+    #[inline]
+    pub unsafe fn unsafe_take_slot<T>(slot: Slot) -> ~T {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        let key = tls_key(idx);
         let void_ptr: *mut c_void = tls::get(key);
         if void_ptr.is_null() {
             rtabort!("thread-local pointer is null. bogus!");
         }
+        check_type::<T>(idx);
+        // The raw pointer is deliberately left in TLS for speed, but the
+        // type tag is cleared so `check_empty` sees this slot as available
+        // to a subsequent `put`/`put_slot` (the scheduler-handoff pattern
+        // this function exists for), rather than aborting on a pointer
+        // that's already been logically taken.
+        clear_type(idx);
         let ptr: ~T = cast::transmute(void_ptr);
         return ptr;
     }
 
     /// Check whether there is a thread-local pointer installed.
     pub fn exists() -> bool {
+        exists_slot(TASK_SLOT)
+    }
+
+    /// Like `exists`, but for an arbitrary slot rather than `TASK_SLOT`.
+    pub fn exists_slot(slot: Slot) -> bool {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
         unsafe {
-            match maybe_tls_key() {
+            match maybe_tls_key_slot(Slot(idx)) {
                 Some(key) => tls::get(key).is_not_null(),
                 None => false
             }
@@ -221,21 +495,39 @@ pub mod native {
     /// Because this leaves the value in thread-local storage it is possible
     /// For the Scheduler pointer to be aliased
     pub unsafe fn unsafe_borrow<T>() -> *mut T {
-        let key = tls_key();
+        unsafe_borrow_slot(TASK_SLOT)
+    }
+
+    /// Like `unsafe_borrow`, but for an arbitrary slot rather than
+    /// `TASK_SLOT`.
+    pub unsafe fn unsafe_borrow_slot<T>(slot: Slot) -> *mut T {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        let key = tls_key(idx);
         let void_ptr = tls::get(key);
         if void_ptr.is_null() {
             rtabort!("thread-local pointer is null. bogus!");
         }
+        check_type::<T>(idx);
         void_ptr as *mut T
     }
 
     pub unsafe fn try_unsafe_borrow<T>() -> Option<*mut T> {
-        match maybe_tls_key() {
+        try_unsafe_borrow_slot(TASK_SLOT)
+    }
+
+    /// Like `try_unsafe_borrow`, but for an arbitrary slot rather than
+    /// `TASK_SLOT`.
+    pub unsafe fn try_unsafe_borrow_slot<T>(slot: Slot) -> Option<*mut T> {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        match maybe_tls_key_slot(Slot(idx)) {
             Some(key) => {
                 let void_ptr = tls::get(key);
                 if void_ptr.is_null() {
                     None
                 } else {
+                    check_type::<T>(idx);
                     Some(void_ptr as *mut T)
                 }
             }
@@ -244,16 +536,34 @@ pub mod native {
     }
 
     #[inline]
-    fn tls_key() -> tls::Key {
-        match maybe_tls_key() {
+    fn tls_key(idx: uint) -> tls::Key {
+        match maybe_tls_key_slot(Slot(idx)) {
             Some(key) => key,
-            None => rtabort!("runtime tls key not initialized")
+            None => unsafe { create_key(idx) }
+        }
+    }
+
+    unsafe fn create_key(idx: uint) -> tls::Key {
+        LOCK.lock();
+        if RT_TLS_KEYS[idx] == -1 {
+            tls::create(&mut RT_TLS_KEYS[idx]);
+            create_type_key(idx);
         }
+        let key = RT_TLS_KEYS[idx];
+        LOCK.unlock();
+        key
+    }
+
+    /// Look up the TLS key for `TASK_SLOT`, if one has been created yet.
+    pub fn maybe_tls_key() -> Option<tls::Key> {
+        maybe_tls_key_slot(TASK_SLOT)
     }
 
     #[inline]
     #[cfg(not(test))]
-    pub fn maybe_tls_key() -> Option<tls::Key> {
+    pub fn maybe_tls_key_slot(slot: Slot) -> Option<tls::Key> {
+        let Slot(idx) = slot;
+        super::check_slot(idx);
         unsafe {
             // NB: This is a little racy because, while the key is
             // initalized under a mutex and it's assumed to be initalized
@@ -265,8 +575,8 @@ pub mod native {
             // another thread. I think this is fine since the only action
             // they could take if it was initialized would be to check the
             // thread-local value and see that it's not set.
-            if RT_TLS_KEY != -1 {
-                return Some(RT_TLS_KEY);
+            if RT_TLS_KEYS[idx] != -1 {
+                return Some(RT_TLS_KEYS[idx]);
             } else {
                 return None;
             }
@@ -274,10 +584,134 @@ pub mod native {
     }
 
     #[inline] #[cfg(test)]
-    pub fn maybe_tls_key() -> Option<tls::Key> {
+    pub fn maybe_tls_key_slot(slot: Slot) -> Option<tls::Key> {
         use realstd;
-        unsafe {
-            cast::transmute(realstd::rt::shouldnt_be_public::maybe_tls_key())
+        let Slot(idx) = slot;
+        super::check_slot(idx);
+        let Slot(task_idx) = TASK_SLOT;
+        if idx == task_idx {
+            // TASK_SLOT holds the scheduler's ~Task, which the "real"
+            // runtime underneath the test build also reaches into, so the
+            // key has to be shared rather than created separately here.
+            unsafe {
+                cast::transmute(realstd::rt::shouldnt_be_public::maybe_tls_key())
+            }
+        } else {
+            // Other slots are claimed by subsystems local to this crate;
+            // realstd never creates or touches them, so there's no key to
+            // share and this crate's own registry is authoritative, same
+            // as the `#[cfg(not(test))]` path above.
+            unsafe {
+                if RT_TLS_KEYS[idx] != -1 {
+                    Some(RT_TLS_KEYS[idx])
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// A value that's unique to `T` for the life of the program, used as a
+    /// poor man's `TypeId` for the `#[cfg(rtdebug)]` sanity checks below.
+    ///
+    /// # Caveat
+    ///
+    /// `type_tag::<A>` and `type_tag::<B>` have byte-identical bodies, so a
+    /// linker that does identical-code-folding across monomorphizations can
+    /// fold them to the same address, making `check_type` silently accept a
+    /// real `A`/`B` mismatch. This is a best-effort debug aid, not a sound
+    /// guarantee.
+    #[cfg(rtdebug)]
+    fn type_tag<T>() -> uint {
+        let marker: fn() -> uint = type_tag::<T>;
+        unsafe { cast::transmute(marker) }
+    }
+
+    #[cfg(rtdebug)]
+    unsafe fn create_type_key(idx: uint) {
+        if RT_TLS_TYPE_KEYS[idx] == -1 {
+            tls::create(&mut RT_TLS_TYPE_KEYS[idx]);
+        }
+    }
+    #[cfg(not(rtdebug))]
+    unsafe fn create_type_key(_idx: uint) {}
+
+    #[cfg(rtdebug)]
+    unsafe fn destroy_type_key(idx: uint) {
+        if RT_TLS_TYPE_KEYS[idx] != -1 {
+            tls::destroy(RT_TLS_TYPE_KEYS[idx]);
+            RT_TLS_TYPE_KEYS[idx] = -1;
+        }
+    }
+    #[cfg(not(rtdebug))]
+    unsafe fn destroy_type_key(_idx: uint) {}
+
+    /// `TASK_SLOT`'s data key is aliased to `realstd`'s under `#[cfg(test)]`
+    /// (see `maybe_tls_key_slot` above), bypassing this crate's own
+    /// `create_key`/`create_type_key`, so `RT_TLS_TYPE_KEYS[idx]` is never
+    /// created for that slot and still holds its `-1` sentinel. There is
+    /// nothing for the type-tag checks to read or write here; skip them
+    /// rather than `tls::get`/`tls::set` an uninitialized key.
+    #[cfg(rtdebug, test)]
+    #[inline]
+    fn shares_tag_with_realstd(idx: uint) -> bool {
+        let Slot(task_idx) = TASK_SLOT;
+        idx == task_idx
+    }
+    #[cfg(rtdebug, not(test))]
+    #[inline]
+    fn shares_tag_with_realstd(_idx: uint) -> bool { false }
+
+    /// A null type tag means the slot has no owner: either nothing has been
+    /// `put` yet, or the last `take_slot`/`unsafe_take_slot` cleared it on
+    /// the way out. `unsafe_take_slot` deliberately leaves the raw pointer
+    /// behind `RT_TLS_KEYS` for speed, so `check_empty` must key off the
+    /// type tag rather than the data pointer's nullness.
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn check_empty(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        if tls::get(RT_TLS_TYPE_KEYS[idx]).is_not_null() {
+            rtabort!("local_ptr: put() called but a pointer is already installed in this slot");
         }
     }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn check_empty(_idx: uint) {}
+
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn record_type<T>(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        let tag: *mut c_void = cast::transmute(type_tag::<T>());
+        tls::set(RT_TLS_TYPE_KEYS[idx], tag);
+    }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn record_type<T>(_idx: uint) {}
+
+    /// Mark a slot as having no owner, without touching `RT_TLS_KEYS`. See
+    /// `check_empty`.
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn clear_type(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        tls::set(RT_TLS_TYPE_KEYS[idx], ptr::mut_null());
+    }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn clear_type(_idx: uint) {}
+
+    #[cfg(rtdebug)]
+    #[inline]
+    unsafe fn check_type<T>(idx: uint) {
+        if shares_tag_with_realstd(idx) { return; }
+        let got: uint = cast::transmute(tls::get(RT_TLS_TYPE_KEYS[idx]));
+        if got != type_tag::<T>() {
+            rtabort!("local_ptr: pointer type mismatch");
+        }
+    }
+    #[cfg(not(rtdebug))]
+    #[inline]
+    unsafe fn check_type<T>(_idx: uint) {}
 }